@@ -1,6 +1,10 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use super::event;
 use super::EventListenerHandle;
-use crate::dpi::PhysicalPosition;
+use crate::dpi::{LogicalSize, PhysicalPosition, PhysicalSize};
 use crate::event::{Force, MouseButton};
 use crate::keyboard::ModifiersState;
 
@@ -9,25 +13,111 @@ use wasm_bindgen::prelude::wasm_bindgen;
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::PointerEvent;
 
+#[wasm_bindgen]
+extern "C" {
+    // extend `web_sys::PointerEvent` with getters to feature-detect coalesced/predicted
+    // events, `getPredictedEvents` itself, and the stylus and contact-geometry attributes.
+    #[wasm_bindgen(extends = PointerEvent)]
+    type PointerEventExt;
+
+    // coalesced events are not available on Safari
+    #[wasm_bindgen(method, getter, js_name = getCoalescedEvents)]
+    fn has_get_coalesced_events(this: &PointerEventExt) -> JsValue;
+
+    // predicted events are not available on Safari/Firefox
+    #[wasm_bindgen(method, getter, js_name = getPredictedEvents)]
+    fn has_get_predicted_events(this: &PointerEventExt) -> JsValue;
+
+    #[wasm_bindgen(method, js_name = getPredictedEvents)]
+    fn get_predicted_events(this: &PointerEventExt) -> js_sys::Array;
+
+    // stylus tilt along the X/Y axis in degrees, `-90..90`
+    #[wasm_bindgen(method, getter, js_name = tiltX)]
+    fn tilt_x(this: &PointerEventExt) -> i32;
+
+    #[wasm_bindgen(method, getter, js_name = tiltY)]
+    fn tilt_y(this: &PointerEventExt) -> i32;
+
+    // clockwise rotation of the stylus in degrees, `0..359`
+    #[wasm_bindgen(method, getter)]
+    fn twist(this: &PointerEventExt) -> i32;
+
+    // tangential ("barrel") pressure, `-1..1`
+    #[wasm_bindgen(method, getter, js_name = tangentialPressure)]
+    fn tangential_pressure(this: &PointerEventExt) -> f32;
+
+    // width/height of the contact geometry in CSS pixels
+    #[wasm_bindgen(method, getter)]
+    fn width(this: &PointerEventExt) -> f64;
+
+    #[wasm_bindgen(method, getter)]
+    fn height(this: &PointerEventExt) -> f64;
+}
+
+// contact ellipse of a touch in physical pixels; a `0`/`0` report from older engines
+// naturally yields a zero-sized contact.
+fn contact_size(event: &PointerEventExt) -> PhysicalSize<f64> {
+    LogicalSize::new(event.width(), event.height()).to_physical(super::super::scale_factor())
+}
+
+// last-seen button state for a pointer that is currently down, keyed by `pointer_id`,
+// so releases can be synthesized if the browser never delivers a matching `pointerup`.
+type ActivePointers = Rc<RefCell<HashMap<i32, ButtonsState>>>;
+
+// remember the buttons held by a pointer so a later cancel/capture-loss can replay
+// the releases the browser skipped.
+fn remember_pointer(pointers: &ActivePointers, id: i32, buttons: ButtonsState) {
+    pointers.borrow_mut().insert(id, buttons);
+}
+
+// drop the pointer's entry, yielding the buttons it still held so the caller can
+// synthesize a release for each of them.
+fn forget_pointer(pointers: &ActivePointers, id: i32) -> ButtonsState {
+    pointers
+        .borrow_mut()
+        .remove(&id)
+        .unwrap_or_else(ButtonsState::empty)
+}
+
+// the individual mouse buttons set in a `ButtonsState`, in a stable order.
+fn held_buttons(buttons: ButtonsState) -> impl Iterator<Item = MouseButton> {
+    [
+        (ButtonsState::LEFT, MouseButton::Left),
+        (ButtonsState::RIGHT, MouseButton::Right),
+        (ButtonsState::MIDDLE, MouseButton::Middle),
+        (ButtonsState::BACK, MouseButton::Back),
+        (ButtonsState::FORWARD, MouseButton::Forward),
+    ]
+    .into_iter()
+    .filter(move |(flag, _)| buttons.contains(*flag))
+    .map(|(_, button)| button)
+}
+
 #[allow(dead_code)]
 pub(super) struct PointerHandler {
+    active_pointers: ActivePointers,
     on_cursor_leave: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
     on_cursor_enter: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
     on_cursor_move: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
     on_pointer_press: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
     on_pointer_release: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
     on_touch_cancel: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
+    on_lost_pointer_capture: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
+    on_got_pointer_capture: Option<EventListenerHandle<dyn FnMut(PointerEvent)>>,
 }
 
 impl PointerHandler {
     pub fn new() -> Self {
         Self {
+            active_pointers: Rc::new(RefCell::new(HashMap::new())),
             on_cursor_leave: None,
             on_cursor_enter: None,
             on_cursor_move: None,
             on_pointer_press: None,
             on_pointer_release: None,
             on_touch_cancel: None,
+            on_lost_pointer_capture: None,
+            on_got_pointer_capture: None,
         }
     }
 
@@ -69,57 +159,103 @@ impl PointerHandler {
         ));
     }
 
-    pub fn on_mouse_release<M, T>(
+    pub fn on_mouse_release<M, T, P>(
         &mut self,
         canvas_common: &super::Common,
         mut mouse_handler: M,
         mut touch_handler: T,
+        mut pen_handler: P,
     ) where
         M: 'static + FnMut(i32, MouseButton, ModifiersState),
-        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force),
+        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force, PhysicalSize<f64>),
+        P: 'static
+            + FnMut(i32, PhysicalPosition<f64>, f64, f64, f64, Force, f64, ModifiersState),
     {
         let canvas = canvas_common.raw.clone();
+        let active_pointers = self.active_pointers.clone();
         self.on_pointer_release = Some(canvas_common.add_user_event(
             "pointerup",
             move |event: PointerEvent| {
+                // record the buttons still down after this release so cancel/capture-loss
+                // only replays buttons that were genuinely held. When nothing is left down
+                // drop the entry instead of storing an empty state: touch/pen mint a fresh
+                // `pointer_id` per contact, so keeping empties would grow `active_pointers`
+                // without bound over a touch-heavy/inking session.
+                let buttons = event::mouse_buttons(&event);
+                if buttons.is_empty() {
+                    forget_pointer(&active_pointers, event.pointer_id());
+                } else {
+                    remember_pointer(&active_pointers, event.pointer_id(), buttons);
+                }
+
                 match event.pointer_type().as_str() {
-                    "touch" => touch_handler(
-                        event.pointer_id(),
-                        event::touch_position(&event, &canvas)
-                            .to_physical(super::super::scale_factor()),
-                        Force::Normalized(event.pressure() as f64),
-                    ),
+                    "touch" => {
+                        let event: PointerEventExt = event.unchecked_into();
+                        touch_handler(
+                            event.pointer_id(),
+                            event::touch_position(&event, &canvas)
+                                .to_physical(super::super::scale_factor()),
+                            Force::Normalized(event.pressure() as f64),
+                            contact_size(&event),
+                        )
+                    }
                     "mouse" => mouse_handler(
                         event.pointer_id(),
                         event::mouse_button(&event).expect("no mouse button released"),
                         event::mouse_modifiers(&event),
                     ),
+                    "pen" => {
+                        let event: PointerEventExt = event.unchecked_into();
+                        pen_handler(
+                            event.pointer_id(),
+                            event::mouse_position(&event)
+                                .to_physical(super::super::scale_factor()),
+                            event.tilt_x() as f64,
+                            event.tilt_y() as f64,
+                            event.twist() as f64,
+                            Force::Normalized(event.pressure() as f64),
+                            event.tangential_pressure() as f64,
+                            event::mouse_modifiers(&event),
+                        );
+                    }
                     _ => (),
                 }
             },
         ));
     }
 
-    pub fn on_mouse_press<M, T>(
+    pub fn on_mouse_press<M, T, P>(
         &mut self,
         canvas_common: &super::Common,
         mut mouse_handler: M,
         mut touch_handler: T,
+        mut pen_handler: P,
     ) where
         M: 'static + FnMut(i32, PhysicalPosition<f64>, MouseButton, ModifiersState),
-        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force),
+        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force, PhysicalSize<f64>),
+        P: 'static
+            + FnMut(i32, PhysicalPosition<f64>, f64, f64, f64, Force, f64, ModifiersState),
     {
         let canvas = canvas_common.raw.clone();
+        let active_pointers = self.active_pointers.clone();
         self.on_pointer_press = Some(canvas_common.add_user_event(
             "pointerdown",
             move |event: PointerEvent| {
+                remember_pointer(
+                    &active_pointers,
+                    event.pointer_id(),
+                    event::mouse_buttons(&event),
+                );
+
                 match event.pointer_type().as_str() {
                     "touch" => {
+                        let event: PointerEventExt = event.unchecked_into();
                         touch_handler(
                             event.pointer_id(),
                             event::touch_position(&event, &canvas)
                                 .to_physical(super::super::scale_factor()),
                             Force::Normalized(event.pressure() as f64),
+                            contact_size(&event),
                         );
                     }
                     "mouse" => {
@@ -135,17 +271,37 @@ impl PointerHandler {
                         // this could fail, that we care if it fails.
                         let _e = canvas.set_pointer_capture(event.pointer_id());
                     }
+                    "pen" => {
+                        let event: PointerEventExt = event.unchecked_into();
+                        pen_handler(
+                            event.pointer_id(),
+                            event::mouse_position(&event)
+                                .to_physical(super::super::scale_factor()),
+                            event.tilt_x() as f64,
+                            event.tilt_y() as f64,
+                            event.twist() as f64,
+                            Force::Normalized(event.pressure() as f64),
+                            event.tangential_pressure() as f64,
+                            event::mouse_modifiers(&event),
+                        );
+
+                        // capture the pen exactly like the mouse so subsequent moves keep
+                        // targeting the canvas even if the stylus leaves it.
+                        let _e = canvas.set_pointer_capture(event.pointer_id());
+                    }
                     _ => (),
                 }
             },
         ));
     }
 
-    pub fn on_cursor_move<M, T>(
+    pub fn on_cursor_move<M, T, P, R>(
         &mut self,
         canvas_common: &super::Common,
         mut mouse_handler: M,
         mut touch_handler: T,
+        mut pen_handler: P,
+        mut predicted_handler: R,
         prevent_default: bool,
     ) where
         M: 'static
@@ -157,22 +313,30 @@ impl PointerHandler {
                 ButtonsState,
                 Option<MouseButton>,
             ),
-        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force),
+        T: 'static + FnMut(i32, PhysicalPosition<f64>, Force, PhysicalSize<f64>),
+        P: 'static
+            + FnMut(i32, PhysicalPosition<f64>, f64, f64, f64, Force, f64, ModifiersState),
+        R: 'static
+            + FnMut(
+                bool,
+                i32,
+                PhysicalPosition<f64>,
+                PhysicalPosition<f64>,
+                ModifiersState,
+                ButtonsState,
+                Option<MouseButton>,
+                f64,
+                f64,
+                f64,
+                Force,
+                f64,
+            ),
     {
         let canvas = canvas_common.raw.clone();
+        let active_pointers = self.active_pointers.clone();
         self.on_cursor_move = Some(canvas_common.add_event(
             "pointermove",
             move |event: PointerEvent| {
-                // coalesced events are not available on Safari
-                #[wasm_bindgen]
-                extern "C" {
-                    #[wasm_bindgen(extends = PointerEvent)]
-                    type PointerEventExt;
-
-                    #[wasm_bindgen(method, getter, js_name = getCoalescedEvents)]
-                    fn has_get_coalesced_events(this: &PointerEventExt) -> JsValue;
-                }
-
                 match event.pointer_type().as_str() {
                     "touch" => {
                         if prevent_default {
@@ -180,7 +344,7 @@ impl PointerHandler {
                             event.prevent_default();
                         }
                     }
-                    "mouse" => (),
+                    "mouse" | "pen" => (),
                     _ => return,
                 }
 
@@ -196,12 +360,32 @@ impl PointerHandler {
                     )
                 });
 
+                // keep the held-button set current for mouse/pen so a cancel or capture
+                // loss mid-drag replays the right releases. Mirror the release path: a
+                // button-less move (e.g. a hovering pen) forgets the pointer rather than
+                // storing an empty entry, otherwise a hover that leaves via `pointerout`
+                // would never be pruned and `active_pointers` would leak on id churn.
+                if event.pointer_type() != "touch" {
+                    let buttons = event::mouse_buttons(&event);
+                    if buttons.is_empty() {
+                        forget_pointer(&active_pointers, id);
+                    } else {
+                        remember_pointer(&active_pointers, id, buttons);
+                    }
+                }
+
                 // store coalesced events to extend it's lifetime
                 let events = (!event.has_get_coalesced_events().is_undefined())
                     .then(|| event.get_coalesced_events())
                     // if coalesced events is empty, it's a chorded button event
                     .filter(|events| events.length() != 0);
 
+                // store predicted events before `event` is potentially moved into the
+                // iterator below; skip entirely on an undefined or empty list
+                let predicted = (!event.has_get_predicted_events().is_undefined())
+                    .then(|| event.get_predicted_events())
+                    .filter(|predicted| predicted.length() != 0);
+
                 // make a single iterator depending on the availability of coalesced events
                 let events = if let Some(events) = &events {
                     None.into_iter().chain(
@@ -216,7 +400,7 @@ impl PointerHandler {
                 for event in events {
                     // coalesced events should always have the same source as the root event
                     debug_assert_eq!(id, event.pointer_id());
-                    debug_assert_eq!(mouse.is_none(), event.pointer_type() == "touch");
+                    debug_assert_eq!(mouse.is_some(), event.pointer_type() == "mouse");
 
                     if let Some((modifiers, buttons, button)) = mouse {
                         // coalesced events should have the same buttons
@@ -231,35 +415,141 @@ impl PointerHandler {
                             buttons,
                             button,
                         );
+                    } else if event.pointer_type() == "pen" {
+                        pen_handler(
+                            id,
+                            event::mouse_position(&event).to_physical(super::super::scale_factor()),
+                            event.tilt_x() as f64,
+                            event.tilt_y() as f64,
+                            event.twist() as f64,
+                            Force::Normalized(event.pressure() as f64),
+                            event.tangential_pressure() as f64,
+                            event::mouse_modifiers(&event),
+                        );
                     } else {
                         touch_handler(
                             id,
                             event::touch_position(&event, &canvas)
                                 .to_physical(super::super::scale_factor()),
                             Force::Normalized(event.pressure() as f64),
+                            contact_size(&event),
+                        );
+                    }
+                }
+
+                // dispatch the extrapolated future positions last so the consumer can
+                // render ahead; these are speculative and must be discarded once the next
+                // genuine `pointermove` arrives. The first event of the batch is flagged so
+                // the consumer drops the previous prediction batch before applying this one.
+                // Pen predictions carry the same stylus geometry as the live pen path so
+                // inking renders ahead with tilt/twist/pressure intact; mouse predictions
+                // report the neutral `0.0` attributes. Touch does not predict.
+                if let Some(predicted) = predicted {
+                    let mut batch_start = true;
+                    for event in predicted.iter().map(PointerEventExt::unchecked_from_js) {
+                        debug_assert_eq!(id, event.pointer_id());
+
+                        if event.pointer_type() == "touch" {
+                            continue;
+                        }
+
+                        predicted_handler(
+                            batch_start,
+                            id,
+                            event::mouse_position(&event).to_physical(super::super::scale_factor()),
+                            event::mouse_delta(&event).to_physical(super::super::scale_factor()),
+                            event::mouse_modifiers(&event),
+                            event::mouse_buttons(&event),
+                            event::mouse_button(&event),
+                            event.tilt_x() as f64,
+                            event.tilt_y() as f64,
+                            event.twist() as f64,
+                            Force::Normalized(event.pressure() as f64),
+                            event.tangential_pressure() as f64,
                         );
+                        batch_start = false;
                     }
                 }
             },
         ));
     }
 
-    pub fn on_touch_cancel<F>(&mut self, canvas_common: &super::Common, mut handler: F)
-    where
-        F: 'static + FnMut(i32, PhysicalPosition<f64>, Force),
+    pub fn on_touch_cancel<F, R>(
+        &mut self,
+        canvas_common: &super::Common,
+        mut handler: F,
+        mut release_handler: R,
+    ) where
+        F: 'static + FnMut(i32, PhysicalPosition<f64>, Force, PhysicalSize<f64>),
+        R: 'static + FnMut(i32, MouseButton, ModifiersState),
     {
         let canvas = canvas_common.raw.clone();
+        let active_pointers = self.active_pointers.clone();
         self.on_touch_cancel = Some(canvas_common.add_event(
             "pointercancel",
             move |event: PointerEvent| {
+                let id = event.pointer_id();
+
                 if event.pointer_type() == "touch" {
+                    active_pointers.borrow_mut().remove(&id);
+                    let event: PointerEventExt = event.unchecked_into();
                     handler(
-                        event.pointer_id(),
+                        id,
                         event::touch_position(&event, &canvas)
                             .to_physical(super::super::scale_factor()),
                         Force::Normalized(event.pressure() as f64),
+                        contact_size(&event),
                     );
+                } else {
+                    // a mouse/pen cancel never delivers the paired `pointerup`, so replay
+                    // a release for every button the pointer still held.
+                    let modifiers = event::mouse_modifiers(&event);
+                    for button in held_buttons(forget_pointer(&active_pointers, id)) {
+                        release_handler(id, button, modifiers);
+                    }
+                }
+            },
+        ));
+    }
+
+    pub fn on_lost_pointer_capture<F, R>(
+        &mut self,
+        canvas_common: &super::Common,
+        mut handler: F,
+        mut release_handler: R,
+    ) where
+        F: 'static + FnMut(i32),
+        R: 'static + FnMut(i32, MouseButton, ModifiersState),
+    {
+        let active_pointers = self.active_pointers.clone();
+        self.on_lost_pointer_capture = Some(canvas_common.add_event(
+            "lostpointercapture",
+            move |event: PointerEvent| {
+                let id = event.pointer_id();
+
+                // the browser can release capture implicitly (pointercancel, element
+                // removal, the OS stealing the pointer); replay releases for any held
+                // buttons first so no phantom presses linger.
+                let modifiers = event::mouse_modifiers(&event);
+                for button in held_buttons(forget_pointer(&active_pointers, id)) {
+                    release_handler(id, button, modifiers);
                 }
+
+                // surface the capture loss so the grab state cannot desync from the
+                // actual capture state.
+                handler(id);
+            },
+        ));
+    }
+
+    pub fn on_got_pointer_capture<F>(&mut self, canvas_common: &super::Common, mut handler: F)
+    where
+        F: 'static + FnMut(i32),
+    {
+        self.on_got_pointer_capture = Some(canvas_common.add_event(
+            "gotpointercapture",
+            move |event: PointerEvent| {
+                handler(event.pointer_id());
             },
         ));
     }
@@ -271,5 +561,8 @@ impl PointerHandler {
         self.on_pointer_press = None;
         self.on_pointer_release = None;
         self.on_touch_cancel = None;
+        self.on_lost_pointer_capture = None;
+        self.on_got_pointer_capture = None;
+        self.active_pointers.borrow_mut().clear();
     }
 }